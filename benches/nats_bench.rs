@@ -1,8 +1,6 @@
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use std::time::{Duration, Instant};
 
-use nats;
-
 pub fn pub_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("publish");
     group.warm_up_time(Duration::from_secs(1));
@@ -18,7 +16,7 @@ pub fn pub_benchmark(c: &mut Criterion) {
             b.iter_custom(|n| {
                 let start = Instant::now();
                 for _i in 0..n {
-                    nc.publish("bench", &msg).unwrap();
+                    nc.publish("bench", msg).unwrap();
                 }
                 nc.flush().unwrap();
                 start.elapsed()
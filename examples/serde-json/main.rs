@@ -1,4 +1,3 @@
-use nats;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
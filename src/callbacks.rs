@@ -0,0 +1,64 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::Error;
+
+type CallbackFn = dyn Fn() + Send + Sync;
+type ErrorCallbackFn = dyn Fn(Error, Option<&str>) + Send + Sync;
+
+// An optional user callback for connection events. Cloned along with the options.
+#[derive(Clone, Default)]
+pub(crate) struct Callback(Option<Arc<CallbackFn>>);
+
+impl Callback {
+    pub(crate) fn new<F>(cb: F) -> Callback
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        Callback(Some(Arc::new(cb)))
+    }
+
+    pub(crate) fn call(&self) {
+        if let Some(cb) = &self.0 {
+            cb();
+        }
+    }
+}
+
+impl fmt::Debug for Callback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Callback"),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+// An optional user callback for errors that happen outside of an API call,
+// along with the subject of the affected subscription when known.
+#[derive(Clone, Default)]
+pub(crate) struct ErrorCallback(Option<Arc<ErrorCallbackFn>>);
+
+impl ErrorCallback {
+    pub(crate) fn new<F>(cb: F) -> ErrorCallback
+    where
+        F: Fn(Error, Option<&str>) + Send + Sync + 'static,
+    {
+        ErrorCallback(Some(Arc::new(cb)))
+    }
+
+    pub(crate) fn call(&self, err: Error, subject: Option<&str>) {
+        if let Some(cb) = &self.0 {
+            cb(err, subject);
+        }
+    }
+}
+
+impl fmt::Debug for ErrorCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("ErrorCallback"),
+            None => f.write_str("None"),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// A specialized `Result` type for NATS operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The error type for NATS operations.
+///
+/// It converts to and from `io::Error`, so it can be used with `?` in functions
+/// returning `io::Result`. Converting back recovers the original variant.
+///
+/// # Example
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # let nc = nats::connect("demo.nats.io")?;
+/// match nc.request_timeout("help", "Help me?", std::time::Duration::from_secs(1)) {
+///     Ok(resp) => println!("got {}", resp),
+///     Err(nats::Error::Timeout) => println!("no response"),
+///     Err(e) => return Err(e.into()),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// The server rejected our credentials.
+    AuthorizationViolation,
+    /// We are not allowed to publish or subscribe to the subject.
+    PermissionsViolation(String),
+    /// The subject is not valid for the operation, see the `subject` module.
+    InvalidSubject(String),
+    /// The queue group name is not valid, see the `subject` module.
+    InvalidQueueName(String),
+    /// The header name, or its value, can not be sent.
+    InvalidHeader(String),
+    /// The server does not support message headers.
+    HeadersNotSupported,
+    /// The message is larger than the server accepts.
+    MaxPayloadExceeded,
+    /// The message does not fit in what is left of the reconnect buffer.
+    ReconnectBufferExceeded,
+    /// The server list is empty.
+    NoServers,
+    /// The server stopped answering our PINGs, or closed the connection because
+    /// we stopped answering its PINGs.
+    StaleConnection,
+    /// The operation did not complete in time.
+    Timeout,
+    /// Nobody is subscribed to the subject of a request.
+    NoResponders,
+    /// The connection was closed, or was lost before the operation completed.
+    ConnectionClosed,
+    /// A subscription did not keep up, and messages for it were dropped.
+    SlowConsumer,
+    /// The server sent something we did not understand.
+    Protocol(String),
+    /// Any other error sent by the server.
+    Server(String),
+    /// An I/O error.
+    Io(io::Error),
+}
+
+impl Error {
+    // Classify the description of a `-ERR` sent by the server.
+    pub(crate) fn from_server(err: &str) -> Error {
+        let lower = err.to_ascii_lowercase();
+        if lower.starts_with("authorization violation") {
+            Error::AuthorizationViolation
+        } else if lower.starts_with("permissions violation") {
+            // `Permissions Violation for Publish to "subject"`
+            match err.split('"').nth(1) {
+                Some(subject) => Error::PermissionsViolation(subject.to_string()),
+                None => Error::Server(err.to_string()),
+            }
+        } else if lower.starts_with("maximum payload violation") {
+            Error::MaxPayloadExceeded
+        } else if lower.starts_with("stale connection") {
+            Error::StaleConnection
+        } else {
+            Error::Server(err.to_string())
+        }
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::AuthorizationViolation | Error::PermissionsViolation(_) => {
+                ErrorKind::PermissionDenied
+            }
+            Error::InvalidSubject(_)
+            | Error::InvalidQueueName(_)
+            | Error::InvalidHeader(_)
+            | Error::MaxPayloadExceeded
+            | Error::NoServers => ErrorKind::InvalidInput,
+            Error::HeadersNotSupported => ErrorKind::Unsupported,
+            Error::StaleConnection | Error::Timeout => ErrorKind::TimedOut,
+            Error::ConnectionClosed => ErrorKind::NotConnected,
+            Error::Protocol(_) => ErrorKind::InvalidData,
+            Error::Io(e) => e.kind(),
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AuthorizationViolation => f.write_str("Authorization violation"),
+            Error::PermissionsViolation(subject) => {
+                write!(f, "Permissions violation for subject \"{}\"", subject)
+            }
+            Error::InvalidSubject(subject) => write!(f, "Invalid subject {:?}", subject),
+            Error::InvalidQueueName(queue) => write!(f, "Invalid queue name {:?}", queue),
+            Error::InvalidHeader(name) => write!(f, "Invalid header {:?}", name),
+            Error::HeadersNotSupported => f.write_str("Headers are not supported by the server"),
+            Error::MaxPayloadExceeded => f.write_str("Maximum payload exceeded"),
+            Error::ReconnectBufferExceeded => f.write_str("Reconnect buffer size exceeded"),
+            Error::NoServers => f.write_str("No servers to connect to"),
+            Error::StaleConnection => f.write_str("Stale connection"),
+            Error::Timeout => f.write_str("Timed out"),
+            Error::NoResponders => f.write_str("No responders"),
+            Error::ConnectionClosed => f.write_str("Connection closed"),
+            Error::SlowConsumer => f.write_str("Slow consumer, messages dropped"),
+            Error::Protocol(e) => write!(f, "Protocol error: {}", e),
+            Error::Server(e) => write!(f, "Server error: {}", e),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        // Recover an `Error` that was converted to an `io::Error` on the way.
+        if matches!(err.get_ref(), Some(e) if e.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
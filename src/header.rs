@@ -0,0 +1,216 @@
+use crate::error::{Error, Result};
+
+// The version line starting every header block.
+const VERSION_LINE: &str = "NATS/1.0";
+
+/// Headers sent along with a message, such as tracing ids or content types.
+/// A name may hold several values, and names are case sensitive.
+///
+/// Headers the server adds carry a status, such as `503` when nobody is
+/// subscribed to the subject of a request.
+///
+/// # Example
+/// ```
+/// let mut headers = nats::HeaderMap::new();
+/// headers.insert("Content-Type", "application/json");
+/// headers.append("Trace-Id", "a1");
+/// headers.append("Trace-Id", "b2");
+/// assert_eq!(headers.get("Content-Type"), Some("application/json"));
+/// assert_eq!(headers.get_all("Trace-Id").collect::<Vec<_>>(), ["a1", "b2"]);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeaderMap {
+    status: Option<u16>,
+    description: Option<String>,
+    // In the order they were added, which is the order they are sent in.
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    /// Create an empty set of headers.
+    pub fn new() -> HeaderMap {
+        HeaderMap::default()
+    }
+
+    /// Set the header `name` to `value`, replacing any values it had.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    /// Add `value` to the values of the header `name`.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Returns the first value of the header `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns all values of the header `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Remove the header `name`, returning true if it was set.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|(n, _)| n != name);
+        self.entries.len() != len
+    }
+
+    /// Returns every header name and value, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Returns the number of header values.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if there are no header values.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the status the server set on the message, like `503` for no responders.
+    pub fn status(&self) -> Option<u16> {
+        self.status
+    }
+
+    /// Returns the description that came with the status, if any.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    // Encode the headers for HPUB, failing on names or values that would
+    // break the header block.
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buf = format!("{}\r\n", VERSION_LINE);
+        for (name, value) in &self.entries {
+            let valid_name = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| !c.is_whitespace() && !c.is_control() && c != ':');
+            if !valid_name || value.contains(['\r', '\n']) {
+                return Err(Error::InvalidHeader(name.clone()));
+            }
+            buf.push_str(name);
+            buf.push_str(": ");
+            buf.push_str(value);
+            buf.push_str("\r\n");
+        }
+        buf.push_str("\r\n");
+        Ok(buf.into_bytes())
+    }
+
+    // Decode the header block of an HMSG, `NATS/1.0 [status [description]]`
+    // followed by `Name: value` lines. Bytes that are not UTF-8 are replaced.
+    pub(crate) fn from_bytes(buf: &[u8]) -> Result<HeaderMap> {
+        let invalid = || Error::Protocol("invalid message headers".to_string());
+        let text = String::from_utf8_lossy(buf);
+        let mut lines = text.split("\r\n");
+        let status_line = lines
+            .next()
+            .and_then(|line| line.strip_prefix(VERSION_LINE))
+            .ok_or_else(invalid)?;
+
+        let mut headers = HeaderMap::new();
+        let status_line = status_line.trim();
+        if !status_line.is_empty() {
+            let (code, description) = match status_line.split_once(' ') {
+                Some((code, description)) => (code, description.trim()),
+                None => (status_line, ""),
+            };
+            headers.status = Some(code.parse().map_err(|_| invalid())?);
+            if !description.is_empty() {
+                headers.description = Some(description.to_string());
+            }
+        }
+        for line in lines.filter(|line| !line.is_empty()) {
+            let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+            headers.append(name.trim(), value.trim());
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "application/json");
+        headers.append("Trace-Id", "a1");
+        headers.append("Trace-Id", "b2");
+        let buf = headers.to_bytes().unwrap();
+        assert_eq!(
+            buf,
+            b"NATS/1.0\r\nContent-Type: application/json\r\nTrace-Id: a1\r\nTrace-Id: b2\r\n\r\n"
+        );
+        assert_eq!(HeaderMap::from_bytes(&buf).unwrap(), headers);
+    }
+
+    #[test]
+    fn status_line() {
+        let headers = HeaderMap::from_bytes(b"NATS/1.0\r\n\r\n").unwrap();
+        assert_eq!(headers.status(), None);
+        assert!(headers.is_empty());
+
+        let headers = HeaderMap::from_bytes(b"NATS/1.0 503\r\n\r\n").unwrap();
+        assert_eq!(headers.status(), Some(503));
+        assert_eq!(headers.description(), None);
+
+        let headers =
+            HeaderMap::from_bytes(b"NATS/1.0 408 Request Timeout\r\nNats-Pending: 1\r\n\r\n")
+                .unwrap();
+        assert_eq!(headers.status(), Some(408));
+        assert_eq!(headers.description(), Some("Request Timeout"));
+        assert_eq!(headers.get("Nats-Pending"), Some("1"));
+    }
+
+    #[test]
+    fn invalid_blocks() {
+        for buf in &[
+            &b"HTTP/1.1\r\n\r\n"[..],
+            b"NATS/1.0 abc\r\n\r\n",
+            b"NATS/1.0\r\nNo-Colon\r\n\r\n",
+        ] {
+            assert!(matches!(
+                HeaderMap::from_bytes(buf),
+                Err(Error::Protocol(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn non_utf8_values() {
+        let headers = HeaderMap::from_bytes(b"NATS/1.0\r\nX-Name: caf\xe9\r\n\r\n").unwrap();
+        assert_eq!(headers.get("X-Name"), Some("caf\u{fffd}"));
+    }
+
+    #[test]
+    fn invalid_names_and_values() {
+        for (name, value) in &[
+            ("", "v"),
+            ("Bad Name", "v"),
+            ("Bad:Name", "v"),
+            ("Name", "a\r\nb"),
+        ] {
+            let mut headers = HeaderMap::new();
+            headers.insert(*name, *value);
+            assert!(matches!(headers.to_bytes(), Err(Error::InvalidHeader(_))));
+        }
+    }
+}
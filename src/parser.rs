@@ -1,4 +1,4 @@
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Sender, TrySendError};
 use nom::bytes::streaming::{take_until, take_while, take_while1};
 use nom::character::is_space;
 use nom::character::streaming::crlf;
@@ -6,39 +6,55 @@ use nom::sequence::tuple;
 use nom::Err::Incomplete;
 use nom::IResult;
 use std::collections::{HashMap, VecDeque};
-use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write};
-use std::net::TcpStream;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::mem;
 use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, RwLock};
-
-#[deny(unsafe_code)]
+use std::thread;
+use std::time::Duration;
 
 // Protocol
-const INFO: &'static [u8] = b"INFO";
-const MSG: &'static [u8] = b"MSG";
-const PING: &'static [u8] = b"PING";
-const PONG: &'static [u8] = b"PONG";
-const ERR: &'static [u8] = b"-ERR";
+const INFO: &[u8] = b"INFO";
+const MSG: &[u8] = b"MSG";
+const HMSG: &[u8] = b"HMSG";
+const PING: &[u8] = b"PING";
+const PONG: &[u8] = b"PONG";
+const ERR: &[u8] = b"-ERR";
+const OK: &[u8] = b"+OK";
 
 #[inline(always)]
 fn is_valid_op_char(c: u8) -> bool {
-    (c >= 0x41 && c <= 0x5A) || c == '-' as u8 || c == '+' as u8
+    (0x41..=0x5A).contains(&c) || c == b'-' || c == b'+'
 }
 
 pub(crate) struct ReadLoopState {
-    pub(crate) reader: BufReader<TcpStream>,
+    pub(crate) reader: BufReader<Stream>,
     pub(crate) writer: Arc<Mutex<Outbound>>,
-    pub(crate) subs: Arc<RwLock<HashMap<usize, Sender<Message>>>>,
+    pub(crate) subs: Arc<RwLock<HashMap<usize, Subscriber>>>,
     pub(crate) pongs: Arc<Mutex<VecDeque<Sender<bool>>>>,
+    pub(crate) status: Arc<Mutex<ConnectionStatus>>,
+    pub(crate) info: Arc<RwLock<ServerInfo>>,
+    pub(crate) options: Options,
+    pub(crate) servers: Arc<Mutex<ServerPool>>,
+    pub(crate) stats: Arc<Stats>,
 }
 
-pub(crate) fn read_loop(mut state: &mut ReadLoopState) -> io::Result<()> {
+pub(crate) fn read_loop(state: &mut ReadLoopState) -> Result<()> {
     loop {
         match parse_control_op(&mut state.reader)? {
-            ControlOp::Msg(msg_args) => process_msg(&mut state, msg_args)?,
+            ControlOp::Msg(msg_args) => process_msg(state, msg_args)?,
             ControlOp::Ping => state.send_pong()?,
             ControlOp::Pong => state.process_pong(),
-            _ => println!("Got something else"),
+            ControlOp::Err(e) => state.process_err(e),
+            ControlOp::Ok => {
+                let _ = state.writer.lock().unwrap().ack(Ok(()));
+            }
+            ControlOp::Info(info) => state.process_info(info),
+            ControlOp::Unknown(op) => state.options.error_callback.call(
+                Error::Protocol(format!("Unknown protocol operation {}", op)),
+                None,
+            ),
         }
     }
 }
@@ -47,50 +63,274 @@ impl ReadLoopState {
     fn process_pong(&mut self) {
         let mut pongs = self.pongs.lock().unwrap();
         if let Some(s) = pongs.pop_front() {
-            s.send(true).unwrap();
+            // The waiter may have given up on this PONG.
+            let _ = s.send(true);
+        }
+    }
+
+    // The server sends INFO again when the cluster changes, or when it is about to shut down.
+    fn process_info(&mut self, info: ServerInfo) {
+        let (discovered, servers) = {
+            let mut servers = self.servers.lock().unwrap();
+            (servers.add_discovered(&info.connect_urls), servers.len())
+        };
+        let ldm = info.ldm;
+        self.writer.lock().unwrap().max_payload = info.max_payload;
+        *self.info.write().unwrap() = info;
+        if discovered {
+            self.options.discovered_servers_callback.call();
+        }
+        if ldm {
+            self.options.lame_duck_callback.call();
+            if self.options.lame_duck_migrate && servers > 1 {
+                // Fail the read loop so we reconnect to another server.
+                let mut w = self.writer.lock().unwrap();
+                let _ = w.writer.flush();
+                let _ = w.writer.get_ref().shutdown();
+            }
+        }
+    }
+
+    // Report an error sent by the server. The server closes the connection itself
+    // when the error is fatal, which will trigger a reconnect.
+    fn process_err(&self, err: String) {
+        let subject = permission_violation_subject(&err).map(|s| s.to_string());
+        let mut err = Error::from_server(&err);
+        if self.options.verbose {
+            // In verbose mode this answers an op, someone may be waiting on it.
+            match self.writer.lock().unwrap().ack(Err(err)) {
+                Ok(()) => return,
+                Err(e) => err = e,
+            }
         }
+        self.options.error_callback.call(err, subject.as_deref());
     }
 
     fn send_pong(&self) -> io::Result<()> {
         let w = &mut self.writer.lock().unwrap().writer;
-        w.write(b"PONG\r\n")?;
+        w.write_all(b"PONG\r\n")?;
         w.flush()?;
         Ok(())
     }
+
+    fn set_status(&self, status: ConnectionStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+
+    // Called once the read loop has failed. Redials the server and replays all
+    // subscriptions so existing `Subscription`s keep receiving messages.
+    // Returns an error when the connection was closed or we have given up.
+    pub(crate) fn reconnect(&mut self) -> Result<()> {
+        {
+            let mut w = self.writer.lock().unwrap();
+            if w.closed {
+                return Err(Error::ConnectionClosed);
+            }
+            // Make sure the old socket is gone.
+            let _ = w.writer.get_ref().shutdown();
+            // Hold on to publishes until we are connected again.
+            w.pending = Some(Vec::new());
+            // Nothing we sent will be answered now, pending flushes will
+            // never see their PONG. Cleared under the writer lock so every
+            // PING written from now on is in `pending`, with its waiter kept.
+            w.acks.clear();
+            self.pongs.lock().unwrap().clear();
+        }
+        self.set_status(ConnectionStatus::Disconnected);
+        self.options.disconnect_callback.call();
+
+        self.set_status(ConnectionStatus::Reconnecting);
+
+        let can_redial = self.options.dialer.as_ref().is_none_or(Dialer::can_redial);
+        loop {
+            // A transport given up front is gone for good, close right away.
+            if !can_redial {
+                break;
+            }
+            let server = match self.servers.lock().unwrap().next_server() {
+                Some(server) => server.clone(),
+                None => break,
+            };
+            let wait = server.wait_time(
+                self.options.reconnect_wait,
+                self.options.max_reconnect_wait,
+                self.options.reconnect_jitter,
+            );
+            if wait > Duration::default() {
+                // Unparked early by close().
+                thread::park_timeout(wait);
+            }
+            if self.writer.lock().unwrap().closed {
+                break;
+            }
+
+            let (stream, reader, info) = match self.options.connect_stream(&server) {
+                Ok(conn) => conn,
+                Err(_) => {
+                    self.servers
+                        .lock()
+                        .unwrap()
+                        .failed(self.options.max_reconnects);
+                    continue;
+                }
+            };
+
+            let mut w = self.writer.lock().unwrap();
+            if w.closed {
+                let _ = stream.shutdown();
+                break;
+            }
+            w.writer = BufWriter::with_capacity(self.options.write_buffer_capacity, stream);
+            let pending = w.pending.take().unwrap_or_default();
+            let replay = |w: &mut Outbound| -> io::Result<()> {
+                for (sid, sub) in self.subs.read().unwrap().iter() {
+                    w.write_sub(&sub.subject, sub.queue.as_deref(), *sid)?;
+                }
+                w.writer.write_all(&pending)?;
+                w.writer.flush()
+            };
+            if replay(&mut w).is_err() {
+                // Keep what was buffered for the next server.
+                let _ = w.writer.get_ref().shutdown();
+                w.pending = Some(pending);
+                w.acks.clear();
+                drop(w);
+                self.servers
+                    .lock()
+                    .unwrap()
+                    .failed(self.options.max_reconnects);
+                continue;
+            }
+            // Answered in the order they went out, subscriptions first.
+            let acks = mem::take(&mut w.pending_acks);
+            w.acks.extend(acks);
+
+            let discovered = {
+                let mut servers = self.servers.lock().unwrap();
+                servers.connected();
+                servers.add_discovered(&info.connect_urls)
+            };
+            w.max_payload = info.max_payload;
+            self.reader = reader;
+            *self.info.write().unwrap() = info;
+            drop(w);
+            self.stats.reconnected();
+            self.set_status(ConnectionStatus::Connected);
+            self.options.reconnect_callback.call();
+            if discovered {
+                self.options.discovered_servers_callback.call();
+            }
+            return Ok(());
+        }
+
+        // We have given up, release everyone waiting on us.
+        {
+            let mut w = self.writer.lock().unwrap();
+            w.closed = true;
+            w.pending = None;
+            w.pending_acks.clear();
+            w.kick_flusher();
+        }
+        self.pongs.lock().unwrap().clear();
+        self.subs.write().unwrap().clear();
+        self.set_status(ConnectionStatus::Closed);
+        Err(Error::ConnectionClosed)
+    }
 }
 
-fn process_msg(state: &mut ReadLoopState, msg_args: MsgArgs) -> io::Result<()> {
+fn process_msg(state: &mut ReadLoopState, msg_args: MsgArgs) -> Result<()> {
+    let reader = &mut state.reader;
+
+    // The header block comes first, and counts towards the message length.
+    let mut bad_headers = None;
+    let headers = match msg_args.hlen {
+        Some(hlen) if hlen <= msg_args.mlen => {
+            let mut buf = Vec::with_capacity(hlen as usize);
+            reader.take(hlen as u64).read_to_end(&mut buf)?;
+            // Sent by a publisher, not the server, so deliver the message without them.
+            match HeaderMap::from_bytes(&buf) {
+                Ok(headers) => Some(headers),
+                Err(e) => {
+                    bad_headers = Some(e);
+                    None
+                }
+            }
+        }
+        Some(_) => return Err(parse_error()),
+        None => None,
+    };
+    let dlen = msg_args.mlen - msg_args.hlen.unwrap_or(0);
+
     let mut msg = Message {
         subject: msg_args.subject,
         reply: msg_args.reply,
-        data: Vec::with_capacity(msg_args.mlen as usize),
+        headers,
+        data: Vec::with_capacity(dlen as usize),
         writer: None,
     };
 
     // Setup so we can send responses.
-    if let Some(_) = msg.reply {
+    if msg.reply.is_some() {
         msg.writer = Some(state.writer.clone());
     }
 
-    let reader = &mut state.reader;
     // FIXME(dlc) - avoid copy if possible.
     // FIXME(dlc) - Just read CRLF? Buffered so should be ok.
-    reader
-        .take(msg_args.mlen as u64)
-        .read_to_end(&mut msg.data)?;
+    reader.take(dlen as u64).read_to_end(&mut msg.data)?;
 
     let mut crlf = [0; 2];
     reader.read_exact(&mut crlf)?;
+    state.stats.received(msg.data.len());
 
     // Now lookup the subscription's channel.
-    let subs = state.subs.read().unwrap();
-    if let Some(tx) = subs.get(&msg_args.sid) {
-        tx.send(msg).unwrap();
+    let (subject, slow) = {
+        let subs = state.subs.read().unwrap();
+        match subs.get(&msg_args.sid) {
+            Some(sub) => {
+                let slow = match sub.tx.try_send(msg) {
+                    Ok(()) => {
+                        sub.slow.store(false, Ordering::Relaxed);
+                        false
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        state.stats.dropped();
+                        !sub.slow.swap(true, Ordering::Relaxed)
+                    }
+                    // The receiving side is already gone.
+                    Err(TrySendError::Disconnected(_)) => false,
+                };
+                let subject = if slow || bad_headers.is_some() {
+                    Some(sub.subject.clone())
+                } else {
+                    None
+                };
+                (subject, slow)
+            }
+            None => (None, false),
+        }
+    };
+    // Not under the lock, the callback may well unsubscribe.
+    if let Some(err) = bad_headers {
+        state.options.error_callback.call(err, subject.as_deref());
+    }
+    if slow {
+        state
+            .options
+            .error_callback
+            .call(Error::SlowConsumer, subject.as_deref());
     }
     Ok(())
 }
 
-pub(crate) fn parse_control_op(reader: &mut BufReader<TcpStream>) -> io::Result<ControlOp> {
+// The server reports denied subscriptions as
+// `Permissions Violation for Subscription to "subject"`.
+fn permission_violation_subject(err: &str) -> Option<&str> {
+    const PREFIX: &str = "Permissions Violation for Subscription to ";
+    let rest = err.strip_prefix(PREFIX)?.trim_start_matches('"');
+    rest.split('"').next()
+}
+
+pub(crate) fn parse_control_op(reader: &mut BufReader<Stream>) -> Result<ControlOp> {
     // This should not do a malloc here so this should be ok.
     let mut buf = Vec::new();
     let (input, start_len, (op, args)) = {
@@ -117,11 +357,13 @@ pub(crate) fn parse_control_op(reader: &mut BufReader<TcpStream>) -> io::Result<
     };
 
     let op = match op {
-        MSG => parse_msg_args(args)?,
+        MSG => parse_msg_args(args, false)?,
+        HMSG => parse_msg_args(args, true)?,
         INFO => parse_info(args)?,
         PING => ControlOp::Ping,
         PONG => ControlOp::Pong,
         ERR => parse_err(args),
+        OK => ControlOp::Ok,
         _ => ControlOp::Unknown(String::from_utf8_lossy(op).to_string()),
     };
 
@@ -132,11 +374,23 @@ pub(crate) fn parse_control_op(reader: &mut BufReader<TcpStream>) -> io::Result<
     Ok(op)
 }
 
-fn parse_msg_args(args: &[u8]) -> io::Result<ControlOp> {
+fn parse_msg_args(args: &[u8], has_headers: bool) -> Result<ControlOp> {
     let a = String::from_utf8_lossy(args);
-    // subject sid <reply> msg_len
+    // subject sid <reply> msg_len, or for HMSG
+    // subject sid <reply> hdr_len total_len
     // TODO(dlc) - convert to nom.
-    let args: Vec<&str> = a.split(" ").collect();
+    let mut args: Vec<&str> = a.split(" ").collect();
+    let hlen = if has_headers {
+        match args.len() {
+            4 | 5 => match u32::from_str(args.remove(args.len() - 2)) {
+                Ok(hlen) => Some(hlen),
+                _ => return Err(parse_error()),
+            },
+            _ => return Err(parse_error()),
+        }
+    } else {
+        None
+    };
     let (subject, len_index, reply) = match args.len() {
         3 => (args[0], 2, None),
         4 => (args[0], 3, Some(args[2].to_owned())),
@@ -152,16 +406,17 @@ fn parse_msg_args(args: &[u8]) -> io::Result<ControlOp> {
     };
     let m = MsgArgs {
         subject: subject.to_owned(),
-        reply: reply,
+        reply,
         //        data: Vec::with_capacity(msg_len as usize),
+        hlen,
         mlen: msg_len,
-        sid: sid,
+        sid,
     };
     Ok(ControlOp::Msg(m))
 }
 
 fn parse_error() -> Error {
-    Error::new(ErrorKind::InvalidInput, "parsing error")
+    Error::Protocol("parsing error".to_string())
 }
 
 fn parse_err(args: &[u8]) -> ControlOp {
@@ -170,11 +425,11 @@ fn parse_err(args: &[u8]) -> ControlOp {
     ControlOp::Err(err_description.to_string())
 }
 
-pub(crate) fn expect_info(reader: &mut BufReader<TcpStream>) -> io::Result<ServerInfo> {
+pub(crate) fn expect_info(reader: &mut BufReader<Stream>) -> Result<ServerInfo> {
     let op = parse_control_op(reader)?;
     match op {
         ControlOp::Info(info) => Ok(info),
-        _ => Err(Error::new(ErrorKind::Other, "INFO proto not found")),
+        _ => Err(Error::Protocol("INFO proto not found".to_string())),
     }
 }
 
@@ -186,12 +441,22 @@ fn control_args(input: &[u8]) -> IResult<&[u8], &[u8]> {
     Ok((input, args))
 }
 
+use super::server_pool::ServerPool;
+use super::stats::Stats;
+use super::stream::Stream;
+use super::transport::Dialer;
+use super::ConnectionStatus;
+use super::Error;
+use super::HeaderMap;
 use super::Message;
+use super::Options;
 use super::Outbound;
+use super::Result;
 use super::ServerInfo;
+use super::Subscriber;
 
-fn parse_info(input: &[u8]) -> io::Result<ControlOp> {
-    let info = serde_json::from_slice(input)?;
+fn parse_info(input: &[u8]) -> Result<ControlOp> {
+    let info = serde_json::from_slice(input).map_err(|e| Error::Protocol(e.to_string()))?;
     Ok(ControlOp::Info(info))
 }
 
@@ -199,6 +464,9 @@ fn parse_info(input: &[u8]) -> io::Result<ControlOp> {
 pub struct MsgArgs {
     subject: String,
     reply: Option<String>,
+    // The length of the header block, for HMSG.
+    hlen: Option<u32>,
+    // The length of the whole message, including the header block.
     mlen: u32,
     sid: usize,
 }
@@ -210,5 +478,95 @@ pub(crate) enum ControlOp {
     Ping,
     Pong,
     Err(String),
+    Ok,
     Unknown(String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::stand_in;
+    use std::sync::mpsc;
+
+    fn msg_args(args: &[u8], has_headers: bool) -> MsgArgs {
+        match parse_msg_args(args, has_headers).unwrap() {
+            ControlOp::Msg(msg_args) => msg_args,
+            op => panic!("unexpected {:?}", op),
+        }
+    }
+
+    #[test]
+    fn msg() {
+        let m = msg_args(b"foo 1 5", false);
+        assert_eq!(
+            (m.subject.as_str(), m.sid, m.reply, m.hlen, m.mlen),
+            ("foo", 1, None, None, 5)
+        );
+        let m = msg_args(b"foo 1 _INBOX.x 5", false);
+        assert_eq!(m.reply.as_deref(), Some("_INBOX.x"));
+        assert_eq!(m.mlen, 5);
+    }
+
+    #[test]
+    fn hmsg() {
+        let m = msg_args(b"foo 1 12 17", true);
+        assert_eq!(
+            (m.subject.as_str(), m.sid, m.reply, m.hlen, m.mlen),
+            ("foo", 1, None, Some(12), 17)
+        );
+        let m = msg_args(b"foo 1 _INBOX.x 12 17", true);
+        assert_eq!(m.reply.as_deref(), Some("_INBOX.x"));
+        assert_eq!((m.hlen, m.mlen), (Some(12), 17));
+    }
+
+    #[test]
+    fn invalid_args() {
+        for (args, has_headers) in &[
+            (&b"foo 1"[..], false),
+            (b"foo x 5", false),
+            (b"foo 1 five", false),
+            (b"foo 1 a b 5", false),
+            (b"foo 1 17", true),
+            (b"foo 1 x 17", true),
+            (b"foo 1 a b 12 17", true),
+        ] {
+            assert!(parse_msg_args(args, *has_headers).is_err());
+        }
+    }
+
+    #[test]
+    fn invalid_headers_keep_the_connection() {
+        let client = stand_in(r#","headers":true"#, |line, server| {
+            if line.starts_with("SUB foo ") {
+                server.write_all(b"HMSG foo 1 22 24\r\nNATS/1.0\r\nNo-Colon\r\n\r\nhi\r\n")?;
+                server.write_all(b"MSG foo 1 5\r\nhello\r\n")?;
+            }
+            Ok(())
+        });
+
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let nc = crate::Connection::new()
+            .error_callback(move |err, subject| {
+                let _ = tx
+                    .lock()
+                    .unwrap()
+                    .send((err.to_string(), subject.map(String::from)));
+            })
+            .with_transport(client)
+            .connect("localhost")
+            .unwrap();
+        let sub = nc.subscribe("foo").unwrap();
+
+        let msg = sub.next_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(msg.data, b"hi");
+        assert!(msg.headers.is_none());
+        let (err, subject) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(err.contains("invalid message headers"));
+        assert_eq!(subject.as_deref(), Some("foo"));
+
+        let msg = sub.next_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(msg.data, b"hello");
+        assert_eq!(nc.status(), ConnectionStatus::Connected);
+    }
+}
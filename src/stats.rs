@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A snapshot of the traffic on a connection, see `Connection::statistics`.
+/// Counts cover the whole life of the connection, across reconnects.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// Messages received from the server, including dropped messages.
+    pub in_msgs: u64,
+    /// Messages published, including responses.
+    pub out_msgs: u64,
+    /// Payload bytes received from the server.
+    pub in_bytes: u64,
+    /// Payload bytes published.
+    pub out_bytes: u64,
+    /// Times the connection was re-established.
+    pub reconnects: u64,
+    /// Messages dropped because their subscription was a slow consumer.
+    pub dropped_msgs: u64,
+}
+
+// The live counters behind `Statistics`, shared by the connection's threads.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    in_msgs: AtomicU64,
+    out_msgs: AtomicU64,
+    in_bytes: AtomicU64,
+    out_bytes: AtomicU64,
+    reconnects: AtomicU64,
+    dropped_msgs: AtomicU64,
+}
+
+impl Stats {
+    pub(crate) fn received(&self, bytes: usize) {
+        self.in_msgs.fetch_add(1, Ordering::Relaxed);
+        self.in_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.out_msgs.fetch_add(1, Ordering::Relaxed);
+        self.out_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.dropped_msgs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Statistics {
+        Statistics {
+            in_msgs: self.in_msgs.load(Ordering::Relaxed),
+            out_msgs: self.out_msgs.load(Ordering::Relaxed),
+            in_bytes: self.in_bytes.load(Ordering::Relaxed),
+            out_bytes: self.out_bytes.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            dropped_msgs: self.dropped_msgs.load(Ordering::Relaxed),
+        }
+    }
+}
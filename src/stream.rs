@@ -0,0 +1,108 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+use crate::transport::Transport;
+
+// The connection to a server. Each handle can be cloned so the read loop
+// and the writer can each own one.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream),
+    // A transport from a user supplied dialer.
+    Custom(Box<dyn Transport>),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(tcp) => Ok(Stream::Tcp(tcp.try_clone()?)),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => Ok(Stream::Tls(tls.try_clone()?)),
+            Stream::Custom(transport) => Ok(Stream::Custom(transport.try_clone()?)),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.get_ref().set_read_timeout(timeout),
+            Stream::Custom(transport) => transport.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.get_ref().set_write_timeout(timeout),
+            Stream::Custom(transport) => transport.set_write_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.shutdown(Shutdown::Both),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.shutdown(),
+            Stream::Custom(transport) => transport.shutdown(),
+        }
+    }
+
+    // The connection as a transport, for layering TLS or websockets on top.
+    pub(crate) fn into_transport(self) -> Box<dyn Transport> {
+        match self {
+            Stream::Tcp(tcp) => Box::new(tcp),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => Box::new(tls),
+            Stream::Custom(transport) => transport,
+        }
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Tcp(tcp) => f.debug_tuple("Tcp").field(tcp).finish(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => f.debug_tuple("Tls").field(tls).finish(),
+            Stream::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.read(buf),
+            Stream::Custom(transport) => transport.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(tcp) => tcp.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.write(buf),
+            Stream::Custom(transport) => transport.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(tcp) => tcp.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.flush(),
+            Stream::Custom(transport) => transport.flush(),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Validation of subjects and queue group names.
+//!
+//! Subjects are made of tokens separated by `.`, like `orders.eu.new`. Subscriptions
+//! may use `*` to match any single token, and end in `>` to match one or more tokens.
+//! Subjects and queue names can not contain whitespace.
+//!
+//! Publishing and subscribing check their arguments with these functions, and fail
+//! with `Error::InvalidSubject` or `Error::InvalidQueueName`.
+//!
+//! # Example
+//! ```
+//! use nats::subject;
+//!
+//! assert!(subject::is_valid_subject("orders.*.new"));
+//! assert!(subject::is_valid_subject("orders.>"));
+//! assert!(!subject::is_valid_subject("orders.>.new"));
+//! assert!(!subject::is_valid_publish_subject("orders.*.new"));
+//! ```
+
+/// Returns true if `subject` can be subscribed to. Every token must be non-empty,
+/// wildcards must be whole tokens, and `>` may only be the last token.
+///
+/// # Example
+/// ```
+/// assert!(nats::subject::is_valid_subject("foo.*.bar"));
+/// assert!(!nats::subject::is_valid_subject("foo..bar"));
+/// assert!(!nats::subject::is_valid_subject("foo*.bar"));
+/// ```
+pub fn is_valid_subject(subject: &str) -> bool {
+    let mut tokens = subject.split('.').peekable();
+    while let Some(token) = tokens.next() {
+        let valid = match token {
+            "" => false,
+            "*" => true,
+            ">" => tokens.peek().is_none(),
+            token => !token.contains(['*', '>']) && !token.chars().any(is_reserved),
+        };
+        if !valid {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns true if `subject` can be published to, or used as a reply subject.
+/// These are valid subjects without wildcards.
+///
+/// # Example
+/// ```
+/// assert!(nats::subject::is_valid_publish_subject("foo.bar"));
+/// assert!(!nats::subject::is_valid_publish_subject("foo.>"));
+/// assert!(!nats::subject::is_valid_publish_subject("foo bar"));
+/// ```
+pub fn is_valid_publish_subject(subject: &str) -> bool {
+    is_valid_subject(subject) && subject.split('.').all(|t| t != "*" && t != ">")
+}
+
+/// Returns true if `queue` can be used as a queue group name.
+///
+/// # Example
+/// ```
+/// assert!(nats::subject::is_valid_queue_name("workers"));
+/// assert!(!nats::subject::is_valid_queue_name("my workers"));
+/// ```
+pub fn is_valid_queue_name(queue: &str) -> bool {
+    !queue.is_empty() && !queue.chars().any(is_reserved)
+}
+
+// Characters that would break the protocol line.
+fn is_reserved(c: char) -> bool {
+    c.is_whitespace() || c.is_control()
+}
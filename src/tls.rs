@@ -0,0 +1,188 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+use crate::transport::Transport;
+
+// Build the rustls configuration from the connection options. The bundled
+// webpki roots are always trusted, along with any additional root certificates.
+pub(crate) fn client_config(
+    root_certificates: &[PathBuf],
+    client_cert: &Option<(PathBuf, PathBuf)>,
+) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for path in root_certificates {
+        for cert in load_certs(path)? {
+            roots.add(cert).map_err(tls_error)?;
+        }
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(roots);
+
+    match client_cert {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(tls_error),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("No private key found in {}", path.display()),
+        )),
+    }
+}
+
+fn tls_error(e: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, e)
+}
+
+// A TLS session over a transport that can be shared between the read loop and the
+// writer. The transport is read without holding the session lock so writers are
+// never blocked by a pending read.
+pub(crate) struct TlsStream {
+    transport: Box<dyn Transport>,
+    session: Arc<Mutex<ClientConnection>>,
+    // TLS records read from the socket but not yet handed to the session.
+    pending: Vec<u8>,
+}
+
+impl TlsStream {
+    // Run the TLS handshake, verifying the server certificate against `host`.
+    pub(crate) fn connect(
+        mut transport: Box<dyn Transport>,
+        host: &str,
+        config: ClientConfig,
+    ) -> io::Result<TlsStream> {
+        let name = ServerName::try_from(host.to_string())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let mut session = ClientConnection::new(Arc::new(config), name).map_err(tls_error)?;
+        while session.is_handshaking() {
+            session.complete_io(&mut transport)?;
+        }
+        Ok(TlsStream {
+            transport,
+            session: Arc::new(Mutex::new(session)),
+            pending: Vec::new(),
+        })
+    }
+
+    pub(crate) fn get_ref(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            transport: self.transport.try_clone()?,
+            session: self.session.clone(),
+            pending: Vec::new(),
+        })
+    }
+
+    pub(crate) fn shutdown(&self) -> io::Result<()> {
+        {
+            let mut session = self.session.lock().unwrap();
+            session.send_close_notify();
+            if let Ok(mut transport) = self.transport.try_clone() {
+                let _ = session.write_tls(&mut transport);
+            }
+        }
+        self.transport.shutdown()
+    }
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TlsStream")
+    }
+}
+
+impl Transport for TlsStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TlsStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TlsStream::shutdown(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.transport.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.transport.set_write_timeout(timeout)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                while !self.pending.is_empty() && session.wants_read() {
+                    let mut records: &[u8] = &self.pending;
+                    let n = session.read_tls(&mut records)?;
+                    self.pending.drain(..n);
+                    session.process_new_packets().map_err(tls_error)?;
+                    while session.wants_write() {
+                        session.write_tls(&mut self.transport)?;
+                    }
+                }
+                match session.reader().read(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            let mut records = [0; 16 * 1024];
+            let n = self.transport.read(&mut records)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            self.pending.extend_from_slice(&records[..n]);
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.writer().write(buf)?;
+        while session.wants_write() {
+            session.write_tls(&mut self.transport)?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.writer().flush()?;
+        while session.wants_write() {
+            session.write_tls(&mut self.transport)?;
+        }
+        self.transport.flush()
+    }
+}
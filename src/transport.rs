@@ -0,0 +1,297 @@
+//! The connections a client can run over.
+//!
+//! Connections use TCP unless a dialer or an already connected transport is given,
+//! see `Connection::with_dialer` and `Connection::with_transport`. Anything that
+//! implements `Transport` can be used, such as a Unix domain socket, or one end of
+//! an in-memory `pipe` in tests.
+//!
+//! # Example
+//! ```
+//! use std::io::{BufRead, BufReader, Write};
+//! use nats::transport::pipe;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let (client, server) = pipe();
+//!
+//! // A stand-in server that answers every PING.
+//! let mut writer = server.clone();
+//! std::thread::spawn(move || -> std::io::Result<()> {
+//!     # writer.write_all(concat!(
+//!     #     r#"INFO {"server_id":"test","server_name":"test","host":"127.0.0.1","port":4222,"#,
+//!     #     r#""version":"2.10.0","max_payload":1048576,"proto":1,"client_id":1,"go":"go1"}"#,
+//!     #     "\r\n"
+//!     # ).as_bytes())?;
+//!     for line in BufReader::new(server).lines() {
+//!         if line? == "PING" {
+//!             writer.write_all(b"PONG\r\n")?;
+//!         }
+//!     }
+//!     Ok(())
+//! });
+//!
+//! let nc = nats::Connection::new()
+//!     .with_transport(client)
+//!     .connect("localhost")?;
+//! nc.flush()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// A connection to a server. The client reads from one handle on its reader thread
+/// while writing to another, so a transport must be able to hand out more handles
+/// to the same connection.
+pub trait Transport: Read + Write + Send {
+    /// Create another handle to the same connection.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+
+    /// Close the connection for every handle. Blocked reads must return.
+    fn shutdown(&self) -> io::Result<()>;
+
+    /// Bound how long reads may block, used during the handshake.
+    /// The default does nothing.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Bound how long writes may block, used during the handshake.
+    /// The default does nothing.
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+// The bytes travelling one way through a pipe.
+#[derive(Debug, Default)]
+struct Channel {
+    state: Mutex<ChannelState>,
+    ready: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Channel {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory connection, see `pipe`.
+#[derive(Clone, Debug)]
+pub struct Pipe {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+}
+
+/// Create an in-memory connection. Bytes written to one end are read from the other.
+/// Shutting down either end closes both, reads then return end of file and writes fail.
+///
+/// # Example
+/// ```
+/// use std::io::{Read, Write};
+/// use nats::transport::{pipe, Transport};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let (mut client, mut server) = pipe();
+/// client.write_all(b"PING\r\n")?;
+/// let mut buf = [0; 6];
+/// server.read_exact(&mut buf)?;
+/// assert_eq!(&buf, b"PING\r\n");
+/// server.shutdown()?;
+/// assert_eq!(client.read(&mut buf)?, 0);
+/// # Ok(())
+/// # }
+/// ```
+pub fn pipe() -> (Pipe, Pipe) {
+    let a = Arc::new(Channel::default());
+    let b = Arc::new(Channel::default());
+    (
+        Pipe {
+            rx: a.clone(),
+            tx: b.clone(),
+        },
+        Pipe { rx: b, tx: a },
+    )
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.rx.state.lock().unwrap();
+        while state.data.is_empty() && !state.closed {
+            state = self.rx.ready.wait(state).unwrap();
+        }
+        let n = buf.len().min(state.data.len());
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "Pipe closed"));
+        }
+        state.data.extend(buf);
+        self.tx.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Pipe {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.rx.close();
+        self.tx.close();
+        Ok(())
+    }
+}
+
+type DialFn = dyn Fn(&str) -> io::Result<Box<dyn Transport>> + Send + Sync;
+
+// Opens the connection to a server, given its `host:port` address.
+#[derive(Clone)]
+pub(crate) struct Dialer {
+    dial: Arc<DialFn>,
+    // Set when the dialer only has a single transport to hand out.
+    once: bool,
+}
+
+impl Dialer {
+    pub(crate) fn new<F>(dial: F) -> Dialer
+    where
+        F: Fn(&str) -> io::Result<Box<dyn Transport>> + Send + Sync + 'static,
+    {
+        Dialer {
+            dial: Arc::new(dial),
+            once: false,
+        }
+    }
+
+    // Hands out the transport to the first dial only, it can not be redialed.
+    pub(crate) fn once(transport: Box<dyn Transport>) -> Dialer {
+        let transport = Mutex::new(Some(transport));
+        let dialer = Dialer::new(move |_| match transport.lock().unwrap().take() {
+            Some(transport) => Ok(transport),
+            None => Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "The transport can not be redialed",
+            )),
+        });
+        Dialer {
+            once: true,
+            ..dialer
+        }
+    }
+
+    pub(crate) fn dial(&self, addr: &str) -> io::Result<Box<dyn Transport>> {
+        (self.dial)(addr)
+    }
+
+    // A `once` dialer has nothing left to reconnect with.
+    pub(crate) fn can_redial(&self) -> bool {
+        !self.once
+    }
+}
+
+impl fmt::Debug for Dialer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Dialer")
+    }
+}
+
+// A stand-in server on the other end of the returned pipe, for tests. It sends an
+// INFO with the `extra` fields, answers every PING, and hands other lines to `on_line`.
+#[cfg(test)]
+pub(crate) fn stand_in<F>(extra: &str, mut on_line: F) -> Pipe
+where
+    F: FnMut(&str, &mut Pipe) -> io::Result<()> + Send + 'static,
+{
+    use std::io::{BufRead, BufReader};
+
+    let (client, server) = pipe();
+    let info = format!(
+        concat!(
+            r#"INFO {{"server_id":"test","server_name":"test","host":"127.0.0.1","port":4222,"#,
+            r#""version":"2.10.0","max_payload":1048576,"proto":1,"client_id":1,"go":"go1"{}}}"#,
+            "\r\n"
+        ),
+        extra
+    );
+    std::thread::spawn(move || -> io::Result<()> {
+        let mut writer = server.clone();
+        writer.write_all(info.as_bytes())?;
+        for line in BufReader::new(server).lines() {
+            let line = line?;
+            if line == "PING" {
+                writer.write_all(b"PONG\r\n")?;
+            } else {
+                on_line(&line, &mut writer)?;
+            }
+        }
+        Ok(())
+    });
+    client
+}
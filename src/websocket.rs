@@ -0,0 +1,362 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::transport::Transport;
+
+// Appended to the key of the upgrade request to derive the accept header (RFC 6455).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// The protocol carried in websocket frames, for servers behind a websocket listener.
+// Every write is sent as one binary frame. Handles share the reading side, so frames
+// are never split between the read loop and the handshake, and the writing side, so
+// control frames answered while reading do not interleave with our writes.
+pub(crate) struct WebSocket {
+    // For socket options and shutdown, which must not wait on a blocked reader or writer.
+    handle: Box<dyn Transport>,
+    reader: Arc<Mutex<FrameReader>>,
+    writer: Arc<Mutex<Box<dyn Transport>>>,
+}
+
+struct FrameReader {
+    reader: BufReader<Box<dyn Transport>>,
+    // The payload of the last data frame, handed out from `pos`.
+    payload: Vec<u8>,
+    pos: usize,
+    closed: bool,
+}
+
+impl WebSocket {
+    // Run the HTTP upgrade handshake for the websocket listener at `path`.
+    pub(crate) fn connect(
+        mut transport: Box<dyn Transport>,
+        host: &str,
+        path: &str,
+    ) -> io::Result<WebSocket> {
+        let key = STANDARD.encode(rand::random::<[u8; 16]>());
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        );
+        transport.write_all(request.as_bytes())?;
+        transport.flush()?;
+
+        let mut reader = BufReader::new(transport.try_clone()?);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.split_whitespace().nth(1) != Some("101") {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Websocket upgrade refused: {}", line.trim()),
+            ));
+        }
+        let mut accept = None;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("sec-websocket-accept") {
+                    accept = Some(value.trim().to_string());
+                }
+            }
+        }
+        let expected = STANDARD.encode(sha1_smol::Sha1::from(key + GUID).digest().bytes());
+        if accept.as_deref() != Some(expected.as_str()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Websocket upgrade returned an invalid accept key",
+            ));
+        }
+
+        Ok(WebSocket {
+            handle: transport.try_clone()?,
+            reader: Arc::new(Mutex::new(FrameReader {
+                reader,
+                payload: Vec::new(),
+                pos: 0,
+                closed: false,
+            })),
+            writer: Arc::new(Mutex::new(transport)),
+        })
+    }
+
+    fn write_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        write_frame(self.writer.lock().unwrap().as_mut(), opcode, payload)
+    }
+}
+
+// Clients must mask every frame they send.
+fn write_frame(w: &mut dyn Write, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => frame.push(0x80 | n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    let mask = rand::random::<[u8; 4]>();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+impl FrameReader {
+    // Read the next frame, returning its opcode and payload.
+    fn read_frame(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head)?;
+        let opcode = head[0] & 0x0F;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0; 2];
+                self.reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            n => n as u64,
+        };
+        let mut mask = None;
+        if head[1] & 0x80 != 0 {
+            let mut key = [0; 4];
+            self.reader.read_exact(&mut key)?;
+            mask = Some(key);
+        }
+        let mut payload = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut payload)?;
+        if (payload.len() as u64) < len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(mask) = mask {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+        }
+        Ok((opcode, payload))
+    }
+}
+
+impl Read for WebSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut r = self.reader.lock().unwrap();
+        while r.pos == r.payload.len() {
+            if r.closed {
+                return Ok(0);
+            }
+            let (opcode, payload) = r.read_frame()?;
+            match opcode {
+                OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                    r.payload = payload;
+                    r.pos = 0;
+                }
+                OP_PING => self.write_frame(OP_PONG, &payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    // Echo the close, the server then closes the connection.
+                    let _ = self.write_frame(OP_CLOSE, &payload);
+                    r.closed = true;
+                }
+                op => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown websocket opcode {}", op),
+                    ))
+                }
+            }
+        }
+        let n = buf.len().min(r.payload.len() - r.pos);
+        buf[..n].copy_from_slice(&r.payload[r.pos..r.pos + n]);
+        r.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for WebSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_frame(OP_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().unwrap().flush()
+    }
+}
+
+impl Transport for WebSocket {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(WebSocket {
+            handle: self.handle.try_clone()?,
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+        }))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        self.handle.shutdown()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.handle.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.handle.set_write_timeout(timeout)
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WebSocket")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{pipe, Pipe};
+    use std::thread;
+
+    fn frame_reader(stream: Pipe) -> FrameReader {
+        FrameReader {
+            reader: BufReader::new(Box::new(stream)),
+            payload: Vec::new(),
+            pos: 0,
+            closed: false,
+        }
+    }
+
+    // A frame as the server sends it, unmasked.
+    fn server_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x80 | opcode, payload.len() as u8];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn frame_lengths() {
+        for &len in &[0, 125, 126, u16::MAX as usize, u16::MAX as usize + 1] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut frame = Vec::new();
+            write_frame(&mut frame, OP_BINARY, &payload).unwrap();
+
+            let header = match len {
+                n if n < 126 => 2,
+                n if n <= u16::MAX as usize => 4,
+                _ => 10,
+            };
+            assert_eq!(frame[0], 0x80 | OP_BINARY);
+            assert_ne!(frame[1] & 0x80, 0, "client frames must be masked");
+            assert_eq!(frame.len(), header + 4 + len);
+
+            let (client, mut server) = pipe();
+            server.write_all(&frame).unwrap();
+            let (opcode, decoded) = frame_reader(client).read_frame().unwrap();
+            assert_eq!(opcode, OP_BINARY);
+            assert_eq!(decoded, payload);
+        }
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let (client, mut server) = pipe();
+        server.write_all(&[0x80 | OP_BINARY, 10, 1, 2, 3]).unwrap();
+        server.shutdown().unwrap();
+        let err = frame_reader(client).read_frame().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn upgrade_and_read() {
+        let (client, mut server) = pipe();
+        let stand_in = thread::spawn(move || -> io::Result<Vec<u8>> {
+            let mut request = Vec::new();
+            let mut byte = [0; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                server.read_exact(&mut byte)?;
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            assert!(request.starts_with("GET /nats HTTP/1.1\r\nHost: localhost:8080\r\n"));
+            let key = request
+                .lines()
+                .find_map(|line| line.strip_prefix("Sec-WebSocket-Key: "))
+                .unwrap();
+            let accept = STANDARD.encode(
+                sha1_smol::Sha1::from(key.to_string() + GUID)
+                    .digest()
+                    .bytes(),
+            );
+            write!(
+                server,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept
+            )?;
+            server.write_all(&server_frame(OP_BINARY, b"INFO {}"))?;
+            server.write_all(&server_frame(OP_PING, b"hi"))?;
+            server.write_all(&server_frame(OP_CONTINUATION, b"\r\n"))?;
+            server.write_all(&server_frame(OP_CLOSE, &[]))?;
+
+            // The PING is answered, then the close echoed.
+            let mut reader = frame_reader(server);
+            let (opcode, pong) = reader.read_frame()?;
+            assert_eq!(opcode, OP_PONG);
+            assert_eq!(reader.read_frame()?.0, OP_CLOSE);
+            Ok(pong)
+        });
+
+        let mut ws = WebSocket::connect(Box::new(client), "localhost:8080", "/nats").unwrap();
+        let mut received = Vec::new();
+        ws.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"INFO {}\r\n");
+        assert_eq!(stand_in.join().unwrap().unwrap(), b"hi");
+    }
+
+    #[test]
+    fn upgrade_refused() {
+        let (client, mut server) = pipe();
+        server
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        let err = WebSocket::connect(Box::new(client), "localhost:8080", "/").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn invalid_accept_key() {
+        let (client, mut server) = pipe();
+        server
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: nope\r\n\r\n")
+            .unwrap();
+        let err = WebSocket::connect(Box::new(client), "localhost:8080", "/").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}